@@ -1,4 +1,5 @@
 extern crate num;
+extern crate rand;
 extern crate rayon;
 
 use std::cmp::max;
@@ -6,6 +7,8 @@ use std::collections::{BinaryHeap, VecDeque};
 use std::collections::HashMap;
 
 use num::complex::Complex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 
 use crate::measurement_ops::measure;
@@ -16,7 +19,11 @@ use crate::utils;
 
 pub enum StateModifierType<P: Precision> {
     UnitaryOp(QubitOp<P>),
-    MeasureState(u64, Vec<u64>)
+    MeasureState(u64, Vec<u64>),
+    /// Apply `op` only if the measurement recorded under `measurement_id` came out as
+    /// `expected`; otherwise a no-op. Lets circuits feed earlier measurement results forward
+    /// into later unitaries, as in teleportation or error correction.
+    ConditionalOp { measurement_id: u64, expected: u64, op: QubitOp<P> }
 }
 
 pub struct StateModifier<P: Precision> {
@@ -38,6 +45,17 @@ impl<P: Precision> StateModifier<P> {
             modifier: StateModifierType::MeasureState(id, indices)
         }
     }
+
+    /// Build a modifier which applies `op` only when the measurement recorded under
+    /// `measurement_id` equals `expected`. The caller is responsible for wiring this modifier
+    /// onto a qubit descended from the one carrying `measurement_id`, so the dependency walk in
+    /// `get_opfns_and_frontier` orders it after that measurement.
+    pub fn new_conditional(name: String, measurement_id: u64, expected: u64, op: QubitOp<P>) -> StateModifier<P> {
+        StateModifier {
+            name,
+            modifier: StateModifierType::ConditionalOp { measurement_id, expected, op }
+        }
+    }
 }
 
 pub struct MeasuredResults<P: Precision> {
@@ -66,6 +84,25 @@ pub trait QuantumState<P: Precision> {
     /// Mutate self with measurement, return result as index and probability
     fn measure(&mut self, indices: &[u64]) -> (u64, P);
 
+    /// Return the marginal probability distribution over `indices`, without mutating state.
+    /// Entry `i` is the probability of the sub-index `i` built from `indices` (qubit at
+    /// `indices[j]` contributes bit `j`).
+    fn probabilities(&self, indices: &[u64]) -> Vec<P>;
+
+    /// Collapse self onto the subspace where `indices` reads as `outcome`, renormalizing by
+    /// `1/sqrt(prob)`. `prob` must be the probability of `outcome`, e.g. from `probabilities`.
+    fn collapse(&mut self, indices: &[u64], outcome: u64, prob: P);
+
+    /// Return the expectation value of the product of Pauli-Z over `indices`, without
+    /// mutating state. Useful for reading out observables during debugging or variational
+    /// loops without paying the cost of a destructive `measure`.
+    fn expectation_z(&self, indices: &[u64]) -> P;
+
+    /// Draw `shots` outcomes from the current state's marginal distribution over `indices`,
+    /// without collapsing or mutating the state. Returns the
+    /// number of times each sub-index was drawn. `seed` makes the draw reproducible.
+    fn sample_measure(&self, indices: &[u64], shots: u64, seed: Option<u64>) -> HashMap<u64, u64>;
+
     /// Consume the QuantumState object and return the state as a vector of complex numbers.
     /// `natural_order` means that qubit with index 0 is the least significant index bit, otherwise
     /// it's the largest.
@@ -74,6 +111,7 @@ pub trait QuantumState<P: Precision> {
 
 /// A basic representation of a quantum state, given by a vector of complex numbers stored
 /// locally on the machine (plus an arena of equal size to work in).
+#[derive(Clone)]
 pub struct LocalQuantumState<P: Precision> {
     // A bundle with the quantum state data.
     pub n: u64,
@@ -89,6 +127,63 @@ pub enum InitialState<P: Precision> {
 
 pub type QubitInitialState<P> = (Vec<u64>, InitialState<P>);
 
+/// Work out the (possibly enlarged) qubit count and the non-|0...0> amplitudes implied by
+/// `states`, without ever materializing a `2^n`-sized buffer: the combinatorial loop below only
+/// runs once per combination of the explicitly-given `FullState` qubits, not once per basis
+/// state of the whole `n`-qubit register. Shared by both `LocalQuantumState` (which scatters the
+/// result into a dense vector) and `SparseQuantumState` (which keeps it as a map).
+fn initial_state_amplitudes<P: Precision>(n: u64, states: &[QubitInitialState<P>]) -> (u64, Vec<(u64, Complex<P>)>) {
+    let max_init_n = states.iter().map(|(indices, _)| indices).cloned().flatten().max().map(|m| m+1);
+    let n = max_init_n.map(|m| max(n, m)).unwrap_or(n);
+
+    // Assume that all unrepresented indices are in the |0> state.
+    let n_fullindices: u64 = states.iter().map(|(indices, state)| {
+        match state {
+            InitialState::FullState(_) => indices.len() as u64,
+            _ => 0
+        }
+    }).sum();
+
+    // Make the index template/base
+    let template: u64 = states.iter().fold(0, |acc, (indices, state)| -> u64 {
+        match state {
+            InitialState::Index(val_indx) => sub_to_full(n, indices, val_indx.clone(), acc),
+            _ => acc
+        }
+    });
+
+    let init = Complex::<P> {
+        re: P::one(),
+        im: P::zero()
+    };
+    // Go through each combination of full index locations
+    let amplitudes = (0 .. 1 << n_fullindices).map(|i| {
+        // Calculate the offset from template, and the product of fullstates.
+        let (delta_index, _, val) = states.iter().fold((0u64, 0u64, init), |acc, (indices, state) | {
+            if let InitialState::FullState(vals) = state {
+                let (superindex_acc, sub_index_offset, val_acc) = acc;
+                // Now we need to make additions to the superindex by adding bits based on
+                // indices, as well as return the value given by the [sub .. sub + len] bits
+                // from i.
+                let index_mask = (1 << indices.len() as u64) - 1;
+                let val_index_bits = (i >> sub_index_offset) & index_mask;
+                let val_acc = val_acc * vals[val_index_bits as usize];
+
+                let superindex_delta: u64 = indices.iter().enumerate().map(|(j,indx)| {
+                    let bit = (val_index_bits >> j as u64) & 1u64;
+                    bit << (n - 1 - indx)
+                }).sum();
+                (superindex_acc + superindex_delta, sub_index_offset + indices.len() as u64, val_acc)
+            } else {
+                acc
+            }
+        });
+        (delta_index + template, val)
+    }).collect();
+
+    (n, amplitudes)
+}
+
 impl<P: Precision> QuantumState<P> for LocalQuantumState<P> {
     /// Build a new LocalQuantumState
     fn new(n: u64) -> LocalQuantumState<P> {
@@ -98,59 +193,15 @@ impl<P: Precision> QuantumState<P> for LocalQuantumState<P> {
     /// Build a local state using a set of initial states for subsets of the qubits.
     /// These initial states are made from the qubit handles.
     fn new_from_initial_states(n: u64, states: &[QubitInitialState<P>]) -> LocalQuantumState<P> {
-        let max_init_n = states.iter().map(|(indices, _)| indices).cloned().flatten().max().map(|m| m+1);
-
-        let n = max_init_n.map(|m| max(n, m)).unwrap_or(n);
+        let (n, amplitudes) = initial_state_amplitudes(n, states);
 
         let mut cvec: Vec<Complex<P>> = (0.. 1 << n).map(|_| Complex::<P> {
             re: P::zero(),
             im: P::zero(),
         }).collect();
-
-        // Assume that all unrepresented indices are in the |0> state.
-        let n_fullindices: u64 = states.iter().map(|(indices, state)| {
-            match state {
-                InitialState::FullState(_) => indices.len() as u64,
-                _ => 0
-            }
-        }).sum();
-
-        // Make the index template/base
-        let template: u64 = states.iter().fold(0, |acc, (indices, state)| -> u64 {
-            match state {
-                InitialState::Index(val_indx) => sub_to_full(n, indices, val_indx.clone(), acc),
-                _ => acc
-            }
-        });
-
-        let init = Complex::<P> {
-            re: P::one(),
-            im: P::zero()
-        };
-        // Go through each combination of full index locations
-        (0 .. 1 << n_fullindices).for_each(|i| {
-            // Calculate the offset from template, and the product of fullstates.
-            let (delta_index, _, val) = states.iter().fold((0u64, 0u64, init), |acc, (indices, state) | {
-                if let InitialState::FullState(vals) = state {
-                    let (superindex_acc, sub_index_offset, val_acc) = acc;
-                    // Now we need to make additions to the superindex by adding bits based on
-                    // indices, as well as return the value given by the [sub .. sub + len] bits
-                    // from i.
-                    let index_mask = (1 << indices.len() as u64) - 1;
-                    let val_index_bits = (i >> sub_index_offset) & index_mask;
-                    let val_acc = val_acc * vals[val_index_bits as usize];
-
-                    let superindex_delta: u64 = indices.iter().enumerate().map(|(j,indx)| {
-                        let bit = (val_index_bits >> j as u64) & 1u64;
-                        bit << (n - 1 - indx)
-                    }).sum();
-                    (superindex_acc + superindex_delta, sub_index_offset + indices.len() as u64, val_acc)
-                } else {
-                    acc
-                }
-            });
-            cvec[(delta_index + template) as usize] = val;
-        });
+        for (index, val) in amplitudes {
+            cvec[index as usize] = val;
+        }
 
         LocalQuantumState {
             n,
@@ -171,6 +222,57 @@ impl<P: Precision> QuantumState<P> for LocalQuantumState<P> {
         measured_result
     }
 
+    fn sample_measure(&self, indices: &[u64], shots: u64, seed: Option<u64>) -> HashMap<u64, u64> {
+        sample_from_buckets(&self.probabilities(indices), shots, seed)
+    }
+
+    fn probabilities(&self, indices: &[u64]) -> Vec<P> {
+        let n = self.n;
+        let len = 1usize << indices.len();
+
+        if self.multithread {
+            let merge = |a: Vec<P>, b: Vec<P>| a.into_iter().zip(b.into_iter()).map(|(x, y)| x + y).collect();
+            self.state.par_iter().enumerate()
+                .fold(|| vec![P::zero(); len], |mut acc, (i, amp)| {
+                    let sub = extract_sub_index(n, i as u64, indices) as usize;
+                    acc[sub] = acc[sub] + amp.norm_sqr();
+                    acc
+                })
+                .reduce(|| vec![P::zero(); len], merge)
+        } else {
+            let mut buckets = vec![P::zero(); len];
+            self.state.iter().enumerate().for_each(|(i, amp)| {
+                let sub = extract_sub_index(n, i as u64, indices) as usize;
+                buckets[sub] = buckets[sub] + amp.norm_sqr();
+            });
+            buckets
+        }
+    }
+
+    fn collapse(&mut self, indices: &[u64], outcome: u64, prob: P) {
+        let n = self.n;
+        let norm = Complex::<P> { re: prob.sqrt(), im: P::zero() };
+        let zero = Complex::<P> { re: P::zero(), im: P::zero() };
+        self.state.iter_mut().enumerate().for_each(|(i, amp)| {
+            let sub = extract_sub_index(n, i as u64, indices);
+            *amp = if sub == outcome { *amp / norm } else { zero };
+        });
+    }
+
+    fn expectation_z(&self, indices: &[u64]) -> P {
+        let n = self.n;
+        let signed_prob = |i: usize, amp: &Complex<P>| sign_of_parity(extract_sub_index(n, i as u64, indices), indices.len()) * amp.norm_sqr();
+
+        if self.multithread {
+            self.state.par_iter().enumerate()
+                .fold(|| P::zero(), |acc, (i, amp)| acc + signed_prob(i, amp))
+                .reduce(|| P::zero(), |a, b| a + b)
+        } else {
+            self.state.iter().enumerate()
+                .fold(P::zero(), |acc, (i, amp)| acc + signed_prob(i, amp))
+        }
+    }
+
     fn get_state(mut self, natural_order: bool) -> Vec<Complex<P>> {
         if natural_order {
             let n = self.n;
@@ -192,15 +294,265 @@ impl<P: Precision> QuantumState<P> for LocalQuantumState<P> {
     }
 }
 
+/// The amplitude magnitude squared below which a `SparseQuantumState` entry is dropped rather
+/// than kept around as noise.
+fn sparse_epsilon<P: Precision>() -> P {
+    P::from(1e-12).unwrap()
+}
+
+/// Extract the sub-index built from the bits of `index` at `indices` (qubit `indices[j]`
+/// contributes bit `j` of the result), using the same qubit-to-bit convention as `split_index`.
+fn extract_sub_index(n: u64, index: u64, indices: &[u64]) -> u64 {
+    indices.iter().enumerate().fold(0u64, |acc, (j, &qi)| {
+        let bit = (index >> (n - 1 - qi)) & 1;
+        acc | (bit << j)
+    })
+}
+
+/// `+1` if `sub` has an even number of set bits among its lowest `len` bits, `-1` otherwise.
+/// Used to fold a basis state's contribution into a product-of-Z expectation value.
+fn sign_of_parity<P: Precision>(sub: u64, len: usize) -> P {
+    let parity = (0 .. len).fold(0u64, |acc, j| acc ^ ((sub >> j) & 1));
+    if parity == 0 { P::one() } else { P::zero() - P::one() }
+}
+
+/// Insert `amp` at `index`, adding to whatever is already there.
+fn accumulate<P: Precision>(state: &mut HashMap<u64, Complex<P>>, index: u64, amp: Complex<P>) {
+    state.entry(index).and_modify(|e| *e = *e + amp).or_insert(amp);
+}
+
+/// Split `index` into the bits untouched by `indices` (the coset it belongs to) and a local
+/// index built from just the bits at `indices`, in the same qubit-to-bit convention used by
+/// `new_from_initial_states` above (qubit `q` lives at bit `n - 1 - q`).
+fn split_index(n: u64, index: u64, indices: &[u64]) -> (u64, usize) {
+    indices.iter().enumerate().fold((index, 0usize), |(base, local), (j, &qi)| {
+        let bit_pos = n - 1 - qi;
+        let bit = (index >> bit_pos) & 1;
+        (base & !(1 << bit_pos), local | ((bit as usize) << j))
+    })
+}
+
+/// Inverse of `split_index`: scatter a local index back onto `indices` within `base`.
+fn join_index(n: u64, base: u64, local: usize, indices: &[u64]) -> u64 {
+    indices.iter().enumerate().fold(base, |index, (j, &qi)| {
+        let bit_pos = n - 1 - qi;
+        let bit = (local >> j) & 1;
+        index | ((bit as u64) << bit_pos)
+    })
+}
+
+/// A representation of a quantum state which only stores non-zero amplitudes, keyed by basis
+/// index. Circuits which stay close to basis-state superpositions (adders, oracles, permutation
+/// networks, QFT inputs) can be simulated well past the point where `LocalQuantumState`'s dense
+/// `2^n`-sized vectors become impractical.
+#[derive(Clone)]
+pub struct SparseQuantumState<P: Precision> {
+    pub n: u64,
+    state: HashMap<u64, Complex<P>>,
+    multithread: bool
+}
+
+impl<P: Precision> QuantumState<P> for SparseQuantumState<P> {
+    /// Build a new SparseQuantumState, initialized to |0...0>.
+    fn new(n: u64) -> SparseQuantumState<P> {
+        let mut state = HashMap::new();
+        state.insert(0, Complex::<P> { re: P::one(), im: P::zero() });
+        SparseQuantumState {
+            n,
+            state,
+            multithread: n > PARALLEL_THRESHOLD
+        }
+    }
+
+    /// Build a sparse state using a set of initial states for subsets of the qubits. Shares the
+    /// index bookkeeping with `LocalQuantumState` via `initial_state_amplitudes`, but writes
+    /// straight into the map instead of ever allocating a `2^n`-sized dense buffer.
+    fn new_from_initial_states(n: u64, states: &[QubitInitialState<P>]) -> SparseQuantumState<P> {
+        let (n, amplitudes) = initial_state_amplitudes(n, states);
+        let epsilon = sparse_epsilon();
+        let state = amplitudes.into_iter()
+            .filter(|(_, amp)| amp.norm_sqr() > epsilon)
+            .collect();
+        SparseQuantumState {
+            n,
+            state,
+            multithread: n > PARALLEL_THRESHOLD
+        }
+    }
+
+    fn apply_op(&mut self, op: &QubitOp<P>) {
+        let indices = op.indices();
+        let k = indices.len();
+        let matrix = op.matrix();
+        let zero = Complex::<P> { re: P::zero(), im: P::zero() };
+        let epsilon = sparse_epsilon();
+        // `split_index` puts `indices[0]` at bit 0 of the local index, while `op`'s matrix treats
+        // `indices[0]` as its most significant bit; reversing the `k` bits maps one to the other.
+        let to_matrix_index = |local: usize| (0 .. k).fold(0usize, |acc, j| acc | (((local >> j) & 1) << (k - 1 - j)));
+
+        // Group the non-zero entries by the bits the op doesn't touch: each such coset is an
+        // independent `2^k`-dimensional subspace that `op` mixes among itself.
+        let mut cosets: HashMap<u64, Vec<(usize, Complex<P>)>> = HashMap::new();
+        for (&index, &amp) in self.state.iter() {
+            let (base, local) = split_index(self.n, index, indices);
+            cosets.entry(base).or_insert_with(Vec::new).push((to_matrix_index(local), amp));
+        }
+
+        let mut new_state = HashMap::new();
+        for (base, entries) in cosets {
+            let mut column = vec![zero; 1 << k];
+            for (local, amp) in entries {
+                column[local] = amp;
+            }
+            for row in 0 .. (1 << k) {
+                let total = (0 .. (1 << k)).fold(zero, |acc, col| {
+                    acc + matrix[row * (1 << k) + col] * column[col]
+                });
+                if total.norm_sqr() > epsilon {
+                    accumulate(&mut new_state, join_index(self.n, base, to_matrix_index(row), indices), total);
+                }
+            }
+        }
+        self.state = new_state;
+    }
+
+    fn measure(&mut self, indices: &[u64]) -> (u64, P) {
+        let probs = self.probabilities(indices);
+        let (outcome, prob) = sample_single(&probs);
+        self.collapse(indices, outcome, prob);
+        (outcome, prob)
+    }
+
+    fn sample_measure(&self, indices: &[u64], shots: u64, seed: Option<u64>) -> HashMap<u64, u64> {
+        sample_from_buckets(&self.probabilities(indices), shots, seed)
+    }
+
+    fn probabilities(&self, indices: &[u64]) -> Vec<P> {
+        let n = self.n;
+        let len = 1usize << indices.len();
+        let merge = |a: Vec<P>, b: Vec<P>| a.into_iter().zip(b.into_iter()).map(|(x, y)| x + y).collect();
+
+        if self.multithread {
+            self.state.par_iter()
+                .fold(|| vec![P::zero(); len], |mut acc, (&index, amp)| {
+                    let sub = extract_sub_index(n, index, indices) as usize;
+                    acc[sub] = acc[sub] + amp.norm_sqr();
+                    acc
+                })
+                .reduce(|| vec![P::zero(); len], merge)
+        } else {
+            let mut buckets = vec![P::zero(); len];
+            for (&index, &amp) in self.state.iter() {
+                let sub = extract_sub_index(n, index, indices) as usize;
+                buckets[sub] = buckets[sub] + amp.norm_sqr();
+            }
+            buckets
+        }
+    }
+
+    fn collapse(&mut self, indices: &[u64], outcome: u64, prob: P) {
+        let n = self.n;
+        let norm = Complex::<P> { re: prob.sqrt(), im: P::zero() };
+        self.state = self.state.iter()
+            .filter(|(&index, _)| split_index(n, index, indices).1 as u64 == outcome)
+            .map(|(&index, &amp)| (index, amp / norm))
+            .collect();
+    }
+
+    fn expectation_z(&self, indices: &[u64]) -> P {
+        let n = self.n;
+        let signed = |index: u64, amp: &Complex<P>| {
+            sign_of_parity(extract_sub_index(n, index, indices), indices.len()) * amp.norm_sqr()
+        };
+
+        if self.multithread {
+            self.state.par_iter()
+                .fold(|| P::zero(), |acc, (&index, amp)| acc + signed(index, amp))
+                .reduce(|| P::zero(), |a, b| a + b)
+        } else {
+            self.state.iter().fold(P::zero(), |acc, (&index, amp)| acc + signed(index, amp))
+        }
+    }
+
+    fn get_state(self, natural_order: bool) -> Vec<Complex<P>> {
+        let zero = Complex::<P> { re: P::zero(), im: P::zero() };
+        let mut dense = vec![zero; 1 << self.n];
+        for (index, amp) in self.state {
+            let index = if natural_order {
+                utils::flip_bits(self.n as usize, index)
+            } else {
+                index
+            };
+            dense[index as usize] = amp;
+        }
+        dense
+    }
+}
+
+/// Build a cumulative distribution from `buckets` (indexed by measured sub-index) and draw
+/// `shots` samples from it via binary search, using a seeded RNG when `seed` is given so draws
+/// are reproducible.
+fn sample_from_buckets<P: Precision>(buckets: &[P], shots: u64, seed: Option<u64>) -> HashMap<u64, u64> {
+    let mut total = P::zero();
+    let cdf: Vec<P> = buckets.iter().map(|&p| {
+        total = total + p;
+        total
+    }).collect();
+
+    let mut rng: StdRng = match seed {
+        Some(seed) => SeedableRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+    };
+
+    let mut counts = HashMap::new();
+    for _ in 0 .. shots {
+        let scaled = P::from(rng.gen::<f64>()).unwrap() * total;
+        let sub = cdf.partition_point(|&c| c < scaled).min(buckets.len() - 1);
+        *counts.entry(sub as u64).or_insert(0u64) += 1;
+    }
+    counts
+}
+
+/// Draw a single outcome from a probability distribution indexed by sub-index, returning the
+/// chosen sub-index and its probability.
+fn sample_single<P: Precision>(buckets: &[P]) -> (u64, P) {
+    let mut total = P::zero();
+    let cdf: Vec<P> = buckets.iter().map(|&p| {
+        total = total + p;
+        total
+    }).collect();
+    let r: P = P::from(rand::thread_rng().gen::<f64>()).unwrap() * total;
+    let outcome = cdf.partition_point(|&c| c < r).min(buckets.len() - 1);
+    (outcome as u64, buckets[outcome])
+}
+
+/// Apply a non-measuring modifier (`UnitaryOp` or `ConditionalOp`) to `state`, looking up prior
+/// measurement outcomes via `outcome_of`. Shared by `fold_modify_state` (single state, outcomes
+/// live in a `MeasuredResults` map) and `run_shots_with_ops` (one state per branch, outcomes
+/// live in that branch's trace) so the two execution engines can't drift apart on what counts
+/// as a matching condition. `MeasureState` is handled by each caller directly, since the two
+/// engines collapse it very differently (destructive single measurement vs. branch splitting).
+fn apply_non_measuring_modifier<P: Precision, QS: QuantumState<P>>(state: &mut QS, modifier: &StateModifierType<P>, outcome_of: impl Fn(u64) -> Option<u64>) {
+    match modifier {
+        StateModifierType::UnitaryOp(op) => state.apply_op(op),
+        StateModifierType::ConditionalOp { measurement_id, expected, op } => {
+            if outcome_of(*measurement_id) == Some(*expected) {
+                state.apply_op(op);
+            }
+        }
+        StateModifierType::MeasureState(..) => unreachable!("MeasureState must be handled by the caller")
+    }
+}
+
 /// Apply an QubitOp to the state `s` and return the new state.
 fn fold_modify_state<P: Precision, QS: QuantumState<P>>(acc: (QS, MeasuredResults<P>), modifier: &StateModifier<P>) -> (QS, MeasuredResults<P>) {
     let (mut s, mut mr) = acc;
     match &modifier.modifier {
-        StateModifierType::UnitaryOp(op) => s.apply_op(op),
         StateModifierType::MeasureState(id, indices) => {
             let result = s.measure(indices);
             mr.results.insert(id.clone(), result);
         }
+        other => apply_non_measuring_modifier(&mut s, other, |id| mr.results.get(&id).map(|(result, _)| *result))
     }
     (s, mr)
 }
@@ -237,6 +589,132 @@ pub fn run_local_with_init<P: Precision>(q: &Qubit<P>, states: &[QubitInitialSta
     run_with_init(q, states)
 }
 
+/// `run` the pipeline using `SparseQuantumState`.
+pub fn run_sparse<P: Precision>(q: &Qubit<P>) -> (SparseQuantumState<P>, MeasuredResults<P>) {
+    run(q)
+}
+
+/// `run_with_init` the pipeline using `SparseQuantumState`
+pub fn run_sparse_with_init<P: Precision>(q: &Qubit<P>, states: &[QubitInitialState<P>]) -> (SparseQuantumState<P>, MeasuredResults<P>) {
+    run_with_init(q, states)
+}
+
+/// Run the circuit for `q` once, then draw `shots` outcomes for `indices` from the resulting
+/// state without collapsing it. Dramatically cheaper than calling `run` once per shot.
+pub fn run_and_sample<P: Precision, QS: QuantumState<P>>(q: &Qubit<P>, indices: &[u64], shots: u64) -> HashMap<u64, u64> {
+    let (state, _) = run::<P, QS>(q);
+    state.sample_measure(indices, shots, None)
+}
+
+/// One branch of a shot-branching execution: a fully evolved state carrying some fraction of
+/// the total shot count, plus the measurement outcomes recorded along the way.
+struct ShotBranch<QS> {
+    state: QS,
+    weight: u64,
+    outcomes: Vec<(u64, u64)>
+}
+
+/// Split `weight` shots across the outcomes in `probs` by drawing a multinomial sample
+/// (implemented as successive binomial draws against the shrinking remainder), so the parts
+/// always sum back to `weight`. Takes the RNG so callers (`run_shots`) can make the whole
+/// branch-splitting process reproducible, the same way `sample_measure` takes a seed.
+fn multinomial_split<P: Precision, R: Rng>(rng: &mut R, weight: u64, probs: &[P]) -> Vec<u64> {
+    let mut remaining_weight = weight;
+    let mut remaining_mass = probs.iter().fold(P::zero(), |acc, &p| acc + p);
+    probs.iter().map(|&p| {
+        if remaining_weight == 0 || remaining_mass <= P::zero() {
+            return 0;
+        }
+        let q = (p / remaining_mass).to_f64().unwrap_or(0.0);
+        let drawn = (0 .. remaining_weight).filter(|_| rng.gen::<f64>() < q).count() as u64;
+        remaining_weight -= drawn;
+        remaining_mass = remaining_mass - p;
+        drawn
+    }).collect()
+}
+
+/// Merge branches that recorded the same outcomes so far: they carry the same state, so only
+/// their shot weights need to be combined. Keeps the branch count bounded across many shots.
+/// The result is sorted by outcome trace, so the order in which later splits draw from the RNG
+/// does not depend on `HashMap` iteration order.
+fn merge_branches<QS>(children: Vec<ShotBranch<QS>>) -> Vec<ShotBranch<QS>> {
+    let mut merged: HashMap<Vec<(u64, u64)>, ShotBranch<QS>> = HashMap::new();
+    for child in children {
+        merged.entry(child.outcomes.clone())
+            .and_modify(|existing| existing.weight += child.weight)
+            .or_insert(child);
+    }
+    let mut branches: Vec<ShotBranch<QS>> = merged.into_iter().map(|(_, branch)| branch).collect();
+    branches.sort_by(|a, b| a.outcomes.cmp(&b.outcomes));
+    branches
+}
+
+/// Core of `run_shots`, taking the already-resolved op list so it can be exercised directly in
+/// tests without needing a `Qubit` circuit graph. `seed` makes the whole branch-splitting process
+/// reproducible, the same way `sample_measure`'s seed does for its single draw.
+fn run_shots_with_ops<P: Precision, QS: QuantumState<P> + Clone>(n: u64, ops: &[&StateModifier<P>], shots: u64, seed: Option<u64>) -> HashMap<Vec<(u64, u64)>, u64> {
+    let mut rng: StdRng = match seed {
+        Some(seed) => SeedableRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+    };
+
+    let mut branches = vec![ShotBranch {
+        state: QS::new(n),
+        weight: shots,
+        outcomes: vec![]
+    }];
+
+    for modifier in ops {
+        match &modifier.modifier {
+            StateModifierType::MeasureState(id, indices) => {
+                let mut children = Vec::new();
+                for branch in branches {
+                    let probs = branch.state.probabilities(indices);
+                    let parts = multinomial_split(&mut rng, branch.weight, &probs);
+                    for (outcome, weight) in parts.into_iter().enumerate() {
+                        if weight == 0 {
+                            continue;
+                        }
+                        let mut state = branch.state.clone();
+                        state.collapse(indices, outcome as u64, probs[outcome]);
+                        let mut outcomes = branch.outcomes.clone();
+                        outcomes.push((*id, outcome as u64));
+                        children.push(ShotBranch { state, weight, outcomes });
+                    }
+                }
+                branches = merge_branches(children);
+            }
+            other => {
+                for branch in branches.iter_mut() {
+                    let outcomes = &branch.outcomes;
+                    apply_non_measuring_modifier(&mut branch.state, other, |id| {
+                        outcomes.iter().find(|(oid, _)| *oid == id).map(|(_, result)| *result)
+                    });
+                }
+            }
+        }
+    }
+
+    let mut histogram = HashMap::new();
+    for branch in branches {
+        *histogram.entry(branch.outcomes).or_insert(0u64) += branch.weight;
+    }
+    histogram
+}
+
+/// Simulate `shots` shots of the circuit for `q`, sharing as much amplitude evolution as
+/// possible across shots instead of re-running the whole pipeline per shot. Each
+/// `MeasureState` op partitions each live branch's shot weight with `multinomial_split` and
+/// splits it into one child per outcome that received at least one shot; branches which have recorded
+/// the same measurement outcomes so far are merged back together to keep the branch count
+/// bounded. Returns a histogram of `(measurement_id, outcome)` traces weighted by shot count.
+/// `seed` makes the run reproducible, as with `QuantumState::sample_measure`.
+pub fn run_shots<P: Precision, QS: QuantumState<P> + Clone>(q: &Qubit<P>, shots: u64, seed: Option<u64>) -> HashMap<Vec<(u64, u64)>, u64> {
+    let (frontier, ops) = get_opfns_and_frontier(q);
+    let n: u64 = frontier.iter().map(|q| q.indices.len() as u64).sum();
+    run_shots_with_ops::<P, QS>(n, &ops, shots, seed)
+}
+
 fn get_opfns_and_frontier<P: Precision>(q: &Qubit<P>) -> (Vec<&Qubit<P>>, Vec<&StateModifier<P>>) {
     let mut heap = BinaryHeap::new();
     heap.push(q);
@@ -277,6 +755,312 @@ fn qubit_in_heap<P: Precision>(q: &Qubit<P>, heap: &BinaryHeap<&Qubit<P>>) -> bo
     false
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a `MatrixOp` for a single-qubit gate, for exercising `apply_op` without needing a
+    /// full circuit builder.
+    fn single_qubit_op(index: u64, matrix: [Complex<f64>; 4]) -> QubitOp<f64> {
+        QubitOp::MatrixOp(vec![index], matrix.to_vec())
+    }
+
+    fn h_op(index: u64) -> QubitOp<f64> {
+        let s = Complex::new(1.0 / 2f64.sqrt(), 0.0);
+        single_qubit_op(index, [s, s, s, -s])
+    }
+
+    fn x_op(index: u64) -> QubitOp<f64> {
+        let zero = Complex::new(0.0, 0.0);
+        let one = Complex::new(1.0, 0.0);
+        single_qubit_op(index, [zero, one, one, zero])
+    }
+
+    /// Two-qubit `MatrixOp` for CNOT, with `control` as the higher-order (first) index so its
+    /// matrix is the usual `[I, 0; 0, X]` block form.
+    fn cnot_op(control: u64, target: u64) -> QubitOp<f64> {
+        let zero = Complex::new(0.0, 0.0);
+        let one = Complex::new(1.0, 0.0);
+        #[rustfmt::skip]
+        let matrix = vec![
+            one,  zero, zero, zero,
+            zero, one,  zero, zero,
+            zero, zero, zero, one,
+            zero, zero, one,  zero,
+        ];
+        QubitOp::MatrixOp(vec![control, target], matrix)
+    }
+
+    /// After a handful of gates starting from the default `|0...0>` state, `SparseQuantumState`
+    /// must agree with `LocalQuantumState` on the resulting dense amplitudes. This exercises the
+    /// `split_index`/`join_index` coset bookkeeping and the `accumulate`/epsilon-prune logic in
+    /// `SparseQuantumState::apply_op` against the already-trusted dense backend.
+    #[test]
+    fn sparse_matches_local_after_gates() {
+        let n = 3u64;
+        let mut local = LocalQuantumState::<f64>::new(n);
+        let mut sparse = SparseQuantumState::<f64>::new(n);
+
+        let ops = [h_op(0), cnot_op(0, 1), h_op(2), cnot_op(1, 2)];
+        for op in &ops {
+            local.apply_op(op);
+            sparse.apply_op(op);
+        }
+
+        let local_state = local.get_state(true);
+        let sparse_state = sparse.get_state(true);
+        assert_eq!(local_state.len(), sparse_state.len());
+        for (a, b) in local_state.iter().zip(sparse_state.iter()) {
+            assert!((a - b).norm() < 1e-9, "expected {:?} to match {:?}", a, b);
+        }
+    }
+
+    /// `sample_measure` should draw exactly `shots` outcomes total from the 50/50 distribution
+    /// left by an `H` gate, covering the CDF build in `sample_from_buckets` and its
+    /// `partition_point` binary search, and the same seed must reproduce the same counts.
+    #[test]
+    fn sample_measure_conserves_shots_and_is_seeded() {
+        let mut state = LocalQuantumState::<f64>::new(1);
+        state.apply_op(&h_op(0));
+
+        let counts_a = state.sample_measure(&[0], 1000, Some(11));
+        let counts_b = state.sample_measure(&[0], 1000, Some(11));
+
+        assert_eq!(counts_a.values().sum::<u64>(), 1000);
+        assert_eq!(counts_a, counts_b);
+        assert_eq!(counts_a.len(), 2);
+    }
+
+    /// `SparseQuantumState::sample_measure` should agree with the dense backend on the same
+    /// 50/50 distribution.
+    #[test]
+    fn sparse_sample_measure_conserves_shots() {
+        let mut state = SparseQuantumState::<f64>::new(1);
+        state.apply_op(&h_op(0));
+
+        let counts = state.sample_measure(&[0], 500, Some(3));
+
+        assert_eq!(counts.values().sum::<u64>(), 500);
+        assert_eq!(counts.len(), 2);
+    }
+
+    /// `run_and_sample` should run the circuit once and draw `shots` outcomes from the resulting
+    /// distribution without collapsing it, so the shot-count invariant holds end to end through
+    /// a real `Qubit`.
+    #[test]
+    fn run_and_sample_conserves_shots() {
+        let root = Qubit { indices: vec![0], parent: None };
+        let h_mod = StateModifier::new_unitary("h".to_string(), h_op(0));
+        let q = Qubit { indices: vec![0], parent: Some(Parent::Owned(vec![root], Some(h_mod))) };
+
+        let counts = run_and_sample::<f64, LocalQuantumState<f64>>(&q, &[0], 200);
+
+        assert_eq!(counts.values().sum::<u64>(), 200);
+        assert_eq!(counts.len(), 2);
+    }
+
+    /// `merge_branches` should sum the weights of branches sharing the same outcome trace and
+    /// leave branches with distinct traces untouched.
+    #[test]
+    fn merge_branches_combines_equal_outcome_traces() {
+        let make_branch = |weight, outcomes: Vec<(u64, u64)>| ShotBranch {
+            state: LocalQuantumState::<f64>::new(1),
+            weight,
+            outcomes
+        };
+        let children = vec![
+            make_branch(3, vec![(0, 1)]),
+            make_branch(5, vec![(0, 1)]),
+            make_branch(2, vec![(0, 0)])
+        ];
+
+        let merged = merge_branches(children);
+
+        assert_eq!(merged.len(), 2);
+        let total: u64 = merged.iter().map(|b| b.weight).sum();
+        assert_eq!(total, 10);
+        let matched = merged.iter().find(|b| b.outcomes == vec![(0, 1)]).unwrap();
+        assert_eq!(matched.weight, 8);
+    }
+
+    /// `run_shots_with_ops` should conserve total shot weight across a mid-circuit measurement,
+    /// and the same seed should reproduce the exact same histogram.
+    #[test]
+    fn run_shots_conserves_weight_and_is_seeded() {
+        let ops = vec![
+            StateModifier::new_unitary("h".to_string(), h_op(0)),
+            StateModifier::new_measurement("m0".to_string(), 0, vec![0])
+        ];
+        let ops: Vec<&StateModifier<f64>> = ops.iter().collect();
+
+        let hist_a = run_shots_with_ops::<f64, LocalQuantumState<f64>>(1, &ops, 1000, Some(7));
+        let hist_b = run_shots_with_ops::<f64, LocalQuantumState<f64>>(1, &ops, 1000, Some(7));
+
+        assert_eq!(hist_a.values().sum::<u64>(), 1000);
+        assert_eq!(hist_a, hist_b);
+        assert_eq!(hist_a.len(), 2);
+    }
+
+    /// Feed-forward, single-shot path: flip a qubit to |1>, measure it, then conditionally
+    /// apply an `X` correction that should only fire when the recorded outcome matches. Mirrors
+    /// the measure-then-correct pattern used by teleportation and error correction.
+    #[test]
+    fn fold_modify_state_applies_conditional_op_on_match() {
+        let state = LocalQuantumState::<f64>::new(1);
+        let (state, mr) = fold_modify_state(
+            (state, MeasuredResults::new()),
+            &StateModifier::new_unitary("x".to_string(), x_op(0))
+        );
+        let (state, mr) = fold_modify_state(
+            (state, mr),
+            &StateModifier::new_measurement("m0".to_string(), 0, vec![0])
+        );
+        assert_eq!(mr.results.get(&0).unwrap().0, 1);
+
+        let (state, _) = fold_modify_state(
+            (state, mr),
+            &StateModifier::new_conditional("fix".to_string(), 0, 1, x_op(0))
+        );
+
+        let amps = state.get_state(true);
+        assert!((amps[0].norm_sqr() - 1.0).abs() < 1e-9, "correction should restore |0>, got {:?}", amps);
+    }
+
+    /// Same feed-forward pattern, but the recorded outcome does NOT match `expected`, so the
+    /// correction must be a no-op.
+    #[test]
+    fn fold_modify_state_skips_conditional_op_on_mismatch() {
+        let state = LocalQuantumState::<f64>::new(1);
+        let (state, mr) = fold_modify_state(
+            (state, MeasuredResults::new()),
+            &StateModifier::new_measurement("m0".to_string(), 0, vec![0])
+        );
+        assert_eq!(mr.results.get(&0).unwrap().0, 0);
+
+        let (state, _) = fold_modify_state(
+            (state, mr),
+            &StateModifier::new_conditional("fix".to_string(), 0, 1, x_op(0))
+        );
+
+        let amps = state.get_state(true);
+        assert!((amps[0].norm_sqr() - 1.0).abs() < 1e-9, "no correction expected, got {:?}", amps);
+    }
+
+    /// The `run_shots` branching path shares `apply_non_measuring_modifier` with
+    /// `fold_modify_state` for the unitary/conditional cases, but still interacts with it
+    /// through its own branch-local outcome trace rather than a `MeasuredResults` map; run the
+    /// same measure-then-correct circuit through it as a regression check. Every shot should
+    /// end up recording (m0=1, m1=0), since the correction always restores |0> before the final
+    /// measurement.
+    #[test]
+    fn run_shots_applies_conditional_correction_per_branch() {
+        let ops = vec![
+            StateModifier::new_unitary("x".to_string(), x_op(0)),
+            StateModifier::new_measurement("m0".to_string(), 0, vec![0]),
+            StateModifier::new_conditional("fix".to_string(), 0, 1, x_op(0)),
+            StateModifier::new_measurement("m1".to_string(), 1, vec![0])
+        ];
+        let ops: Vec<&StateModifier<f64>> = ops.iter().collect();
+
+        let hist = run_shots_with_ops::<f64, LocalQuantumState<f64>>(1, &ops, 100, Some(1));
+
+        assert_eq!(hist.values().sum::<u64>(), 100);
+        for (trace, weight) in &hist {
+            assert_eq!(trace, &vec![(0u64, 1u64), (1u64, 0u64)]);
+            assert_eq!(*weight, 100);
+        }
+    }
+
+    /// Build a tiny real `Qubit` chain: a frontier qubit, a measurement descended from it, and a
+    /// `ConditionalOp` descended from the measurement. Used to check that `get_opfns_and_frontier`
+    /// (and therefore `run`/`run_with_statebuilder`) actually orders the conditional after the
+    /// measurement it depends on, rather than only exercising that ordering via a hand-built op
+    /// list as the other tests in this module do.
+    fn measure_then_correct_qubit(correction: QubitOp<f64>) -> Qubit<f64> {
+        let root = Qubit { indices: vec![0], parent: None };
+        let measure_mod = StateModifier::new_measurement("m0".to_string(), 0, vec![0]);
+        let measured = Qubit { indices: vec![0], parent: Some(Parent::Owned(vec![root], Some(measure_mod))) };
+        let fix_mod = StateModifier::new_conditional("fix".to_string(), 0, 1, correction);
+        Qubit { indices: vec![0], parent: Some(Parent::Owned(vec![measured], Some(fix_mod))) }
+    }
+
+    #[test]
+    fn get_opfns_and_frontier_orders_conditional_after_its_measurement() {
+        let q = measure_then_correct_qubit(x_op(0));
+
+        let (frontier, ops) = get_opfns_and_frontier(&q);
+
+        assert_eq!(frontier.len(), 1);
+        assert_eq!(frontier[0].indices, vec![0]);
+        assert_eq!(ops.len(), 2);
+        match (&ops[0].modifier, &ops[1].modifier) {
+            (StateModifierType::MeasureState(measure_id, _), StateModifierType::ConditionalOp { measurement_id, .. }) => {
+                assert_eq!(measure_id, measurement_id);
+            }
+            _ => panic!("expected the measurement to be ordered before the conditional op depending on it")
+        }
+    }
+
+    /// End-to-end through `run_with_statebuilder`: starting the qubit at |1> makes the
+    /// measurement come out as 1, so the dependent `ConditionalOp` (wired onto a qubit
+    /// descended from the measurement, as `new_conditional`'s docs require) must fire and
+    /// restore |0>, proving the real dependency walk -- not just a hand-ordered op list --
+    /// threads the measurement result into the conditional correctly.
+    #[test]
+    fn run_applies_correction_when_dependency_walk_orders_measurement_first() {
+        let q = measure_then_correct_qubit(x_op(0));
+
+        let (state, mr): (LocalQuantumState<f64>, _) = run_with_statebuilder(&q, |qs| {
+            LocalQuantumState::new_from_initial_states(1, &[(qs[0].indices.clone(), InitialState::Index(1))])
+        });
+
+        assert_eq!(mr.results.get(&0).unwrap().0, 1);
+        let amps = state.get_state(true);
+        assert!((amps[0].norm_sqr() - 1.0).abs() < 1e-9, "correction should restore |0>, got {:?}", amps);
+    }
+
+    /// On the computational basis state |01>, `probabilities` must be fully concentrated on
+    /// that index and `expectation_z` must be exactly -1 (one qubit contributes +1, the other
+    /// -1). Checked on both backends since they implement `extract_sub_index`/`sign_of_parity`
+    /// independently.
+    #[test]
+    fn basis_state_probabilities_and_expectation_z() {
+        let init = vec![(vec![0, 1], InitialState::Index(0b01))];
+
+        let local = LocalQuantumState::<f64>::new_from_initial_states(2, &init);
+        assert_eq!(local.probabilities(&[0, 1])[0b01], 1.0);
+        assert!((local.expectation_z(&[0, 1]) - (-1.0)).abs() < 1e-9);
+
+        let sparse = SparseQuantumState::<f64>::new_from_initial_states(2, &init);
+        assert_eq!(sparse.probabilities(&[0, 1])[0b01], 1.0);
+        assert!((sparse.expectation_z(&[0, 1]) - (-1.0)).abs() < 1e-9);
+    }
+
+    /// On a Bell pair `(|00> + |11>)/sqrt(2)`, the two qubits are perfectly correlated so the
+    /// product-of-Z expectation over both is +1, while each qubit's own marginal is maximally
+    /// mixed so its probabilities are 50/50 and its individual expectation is 0.
+    #[test]
+    fn bell_pair_probabilities_and_expectation_z() {
+        let s = 1.0 / 2f64.sqrt();
+        let zero = Complex::new(0.0, 0.0);
+        let amp = Complex::new(s, 0.0);
+        let init = vec![(vec![0, 1], InitialState::FullState(vec![amp, zero, zero, amp]))];
+
+        let local = LocalQuantumState::<f64>::new_from_initial_states(2, &init);
+        let local_probs = local.probabilities(&[0, 1]);
+        assert!((local_probs[0b00] - 0.5).abs() < 1e-9);
+        assert!((local_probs[0b11] - 0.5).abs() < 1e-9);
+        assert!(local_probs[0b01].abs() < 1e-9);
+        assert!(local_probs[0b10].abs() < 1e-9);
+        assert!((local.expectation_z(&[0, 1]) - 1.0).abs() < 1e-9);
+        assert!(local.expectation_z(&[0]).abs() < 1e-9);
+
+        let sparse = SparseQuantumState::<f64>::new_from_initial_states(2, &init);
+        assert!((sparse.expectation_z(&[0, 1]) - 1.0).abs() < 1e-9);
+        assert!(sparse.expectation_z(&[0]).abs() < 1e-9);
+    }
+}
+
 /// Create a circuit for the circuit given by `q`. If `natural_order`, then the
 /// qubit with index 0 represents the lowest bit in the index of the state (has the smallest
 /// increment when flipped), otherwise it's the largest index (which is the internal state used by